@@ -5,6 +5,8 @@ use std::collections::{
 use std::sync::{Arc};
 use bytes::{Bytes, BytesMut, Buf, BufMut};
 use telnet_codec::{TelnetEvent};
+use serde_json::Value;
+use flate2::{Compress, Decompress, Compression, FlushCompress, FlushDecompress, DecompressError};
 
 const NULL: u8 = 0;
 const BEL: u8 = 7;
@@ -26,36 +28,275 @@ const TELOPT_EOR: u8 = 25;
 const NAWS: u8 = 31;
 // LINEMODE - signifies that the client will not send anything without a line terminator.
 const LINEMODE: u8 = 34;
+// LINEMODE sub-subnegotiation commands (RFC 1184).
+const LINEMODE_MODE: u8 = 1;
+const LINEMODE_SLC: u8 = 3;
+// LINEMODE MODE bits.
+const LINEMODE_MODE_EDIT: u8 = 1;
+const LINEMODE_MODE_TRAPSIG: u8 = 2;
+// SLC levels, packed into the low two bits of an SLC modifier byte.
+const SLC_NOSUPPORT: u8 = 0;
+const SLC_CANTCHANGE: u8 = 1;
+const SLC_VALUE: u8 = 2;
+const SLC_DEFAULT: u8 = 3;
+const SLC_LEVEL_MASK: u8 = 0x03;
+// SLC modifier flag bits.
+const SLC_ACK: u8 = 0x80;
+// SLC function codes we actually track (RFC 1184 defines more).
+const SLC_EOF: u8 = 8;
+const SLC_SUSP: u8 = 9;
+const SLC_EC: u8 = 10;
+const SLC_EL: u8 = 11;
 
 // MNES: Mud New-Environ standard
 const MNES: u8 = 39;
 
+// CHARSET - RFC 2066 charset negotiation
+const CHARSET: u8 = 42;
+const CHARSET_REQUEST: u8 = 1;
+const CHARSET_ACCEPTED: u8 = 2;
+const CHARSET_REJECTED: u8 = 3;
+const CHARSET_TTABLE_IS: u8 = 4;
+// Separator we use when offering our own charset list. RFC 2066 lets the
+// sender pick any character; a semicolon can't appear in a charset name.
+const CHARSET_SEP: u8 = b';';
+const SUPPORTED_CHARSETS: [&str; 3] = ["UTF-8", "ISO-8859-1", "US-ASCII"];
+
 // MUD eXtension Protocol
 const MXP: u8 = 91;
 
 // Mud Server Status Protocol
 const MSSP: u8 = 70;
+const MSSP_VAR: u8 = 1;
+const MSSP_VAL: u8 = 2;
 
 // Compression
 // const MCCP1: u8 = 85 - this is deprecrated
-// NOTE: MCCP2 and MCCP3 is currently disabled.
 const MCCP2: u8 = 86;
 const MCCP3: u8 = 87;
+// Guards against zlib/MCCP decompression bombs: a small, highly compressible
+// inbound chunk must not be allowed to inflate into an unbounded amount of
+// memory. Legitimate game traffic never needs anywhere near this much out of
+// a single chunk.
+const MCCP3_MAX_RATIO: usize = 64;
+const MCCP3_MAX_OUTPUT: usize = 1 << 20;
 
 // GMCP - Generic Mud Communication Protocol
 const GMCP: u8 = 201;
 
 // MSDP - Mud Server Data Protocol
 const MSDP: u8 = 69;
+const MSDP_VAR: u8 = 1;
+const MSDP_VAL: u8 = 2;
+const MSDP_TABLE_OPEN: u8 = 3;
+const MSDP_TABLE_CLOSE: u8 = 4;
+const MSDP_ARRAY_OPEN: u8 = 5;
+const MSDP_ARRAY_CLOSE: u8 = 6;
+
+// Maximum nesting depth for TABLE/ARRAY values. Guards against a crafted
+// subnegotiation of deeply nested TABLE_OPEN/ARRAY_OPEN bytes blowing the
+// stack via unbounded recursion.
+const MSDP_MAX_DEPTH: usize = 32;
 
 // TTYPE - Terminal Type
 const TTYPE: u8 = 24;
 
+// A decoded MSDP value: either a plain string, a table of named values, or
+// an array of values. Tables and arrays may nest arbitrarily.
+#[derive(Debug, Clone, PartialEq)]
+pub enum MsdpValue {
+    Str(String),
+    Table(HashMap<String, MsdpValue>),
+    Array(Vec<MsdpValue>),
+}
+
+// Walks an MSDP subnegotiation payload one control byte at a time.
+struct MsdpReader<'a> {
+    data: &'a [u8],
+    pos: usize,
+}
+
+impl<'a> MsdpReader<'a> {
+    fn new(data: &'a [u8]) -> Self {
+        Self { data, pos: 0 }
+    }
+
+    fn peek(&self) -> Option<u8> {
+        self.data.get(self.pos).copied()
+    }
+
+    fn advance(&mut self) -> Option<u8> {
+        let b = self.peek();
+        if b.is_some() {
+            self.pos += 1;
+        }
+        b
+    }
+
+    // Reads raw bytes up to (not including) the next control byte.
+    fn read_bytes(&mut self) -> Vec<u8> {
+        let start = self.pos;
+        while let Some(b) = self.peek() {
+            if matches!(b, MSDP_VAR | MSDP_VAL | MSDP_TABLE_OPEN | MSDP_TABLE_CLOSE | MSDP_ARRAY_OPEN | MSDP_ARRAY_CLOSE) {
+                break;
+            }
+            self.pos += 1;
+        }
+        self.data[start..self.pos].to_vec()
+    }
+}
+
+fn decode_msdp_value(reader: &mut MsdpReader, depth: usize) -> Option<MsdpValue> {
+    if depth > MSDP_MAX_DEPTH {
+        return None; // nested too deeply, likely a crafted/malformed stream
+    }
+    match reader.peek() {
+        Some(MSDP_TABLE_OPEN) => {
+            reader.advance();
+            let mut table = HashMap::new();
+            loop {
+                match reader.peek() {
+                    Some(MSDP_TABLE_CLOSE) => {
+                        reader.advance();
+                        break;
+                    }
+                    Some(MSDP_VAR) => {
+                        reader.advance();
+                        let name = String::from_utf8(reader.read_bytes()).ok()?;
+                        if reader.advance() != Some(MSDP_VAL) {
+                            return None;
+                        }
+                        table.insert(name, decode_msdp_value(reader, depth + 1)?);
+                    }
+                    _ => return None, // unterminated table
+                }
+            }
+            Some(MsdpValue::Table(table))
+        }
+        Some(MSDP_ARRAY_OPEN) => {
+            reader.advance();
+            let mut items = Vec::new();
+            loop {
+                match reader.peek() {
+                    Some(MSDP_ARRAY_CLOSE) => {
+                        reader.advance();
+                        break;
+                    }
+                    Some(MSDP_VAL) => {
+                        reader.advance();
+                        items.push(decode_msdp_value(reader, depth + 1)?);
+                    }
+                    _ => return None, // unterminated array
+                }
+            }
+            Some(MsdpValue::Array(items))
+        }
+        Some(_) => String::from_utf8(reader.read_bytes()).ok().map(MsdpValue::Str),
+        None => None, // VAL with nothing after it
+    }
+}
+
+// Decodes a flat `VAR name VAL value ...` sequence into name/value pairs.
+// Returns None on any malformed stream (unterminated table/array, a VAL
+// with no preceding VAR, etc.) rather than panicking.
+fn decode_msdp(data: &[u8]) -> Option<Vec<(String, MsdpValue)>> {
+    let mut reader = MsdpReader::new(data);
+    let mut pairs = Vec::new();
+    while reader.peek().is_some() {
+        if reader.advance() != Some(MSDP_VAR) {
+            return None;
+        }
+        let name = String::from_utf8(reader.read_bytes()).ok()?;
+        if reader.advance() != Some(MSDP_VAL) {
+            return None;
+        }
+        pairs.push((name, decode_msdp_value(&mut reader, 0)?));
+    }
+    Some(pairs)
+}
+
+fn encode_msdp_value(value: &MsdpValue, out: &mut BytesMut) {
+    match value {
+        MsdpValue::Str(s) => out.put_slice(s.as_bytes()),
+        MsdpValue::Table(table) => {
+            out.put_u8(MSDP_TABLE_OPEN);
+            for (name, value) in table {
+                out.put_u8(MSDP_VAR);
+                out.put_slice(name.as_bytes());
+                out.put_u8(MSDP_VAL);
+                encode_msdp_value(value, out);
+            }
+            out.put_u8(MSDP_TABLE_CLOSE);
+        }
+        MsdpValue::Array(items) => {
+            out.put_u8(MSDP_ARRAY_OPEN);
+            for item in items {
+                out.put_u8(MSDP_VAL);
+                encode_msdp_value(item, out);
+            }
+            out.put_u8(MSDP_ARRAY_CLOSE);
+        }
+    }
+}
+
+// Encodes MSSP status variables into the `VAR name VAL value` wire format.
+// A variable with multiple values is repeated once per value.
+fn encode_mssp(vars: &HashMap<String, Vec<String>>) -> Bytes {
+    let mut out = BytesMut::new();
+    for (name, values) in vars {
+        for value in values {
+            out.put_u8(MSSP_VAR);
+            out.put_slice(name.as_bytes());
+            out.put_u8(MSSP_VAL);
+            out.put_slice(value.as_bytes());
+        }
+    }
+    out.freeze()
+}
+
+// Encodes name/value pairs into the MSDP wire format.
+fn encode_msdp(pairs: &[(String, MsdpValue)]) -> Bytes {
+    let mut out = BytesMut::new();
+    for (name, value) in pairs {
+        out.put_u8(MSDP_VAR);
+        out.put_slice(name.as_bytes());
+        out.put_u8(MSDP_VAL);
+        encode_msdp_value(value, &mut out);
+    }
+    out.freeze()
+}
+
+// The RFC 1143 Q Method state for one side (local or remote) of an option.
+// NO/YES track the settled states; WANTNO/WANTYES track an in-flight request
+// we made ourselves, pending the peer's reply.
+#[derive(Debug, Default, Clone, Copy, PartialEq, Eq)]
+pub enum QState {
+    #[default]
+    No,
+    Yes,
+    WantNo,
+    WantYes,
+}
+
+// While a WANTNO/WANTYES request is outstanding, Opposite records that we've
+// since changed our mind and want the other thing once this one settles.
+#[derive(Debug, Default, Clone, Copy, PartialEq, Eq)]
+pub enum QQueue {
+    #[default]
+    Empty,
+    Opposite,
+}
+
 #[derive(Default, Clone)]
 pub struct TelnetOptionPerspective {
-    pub enabled: bool,
-    // Negotiating is true if WE have sent a request.
-    pub negotiating: bool
+    pub state: QState,
+    pub queue: QQueue,
+}
+
+impl TelnetOptionPerspective {
+    pub fn is_enabled(&self) -> bool {
+        self.state == QState::Yes
+    }
 }
 
 #[derive(Default, Clone)]
@@ -81,7 +322,14 @@ pub struct TelnetConfig {
     pub width: u16,
     pub height: u16,
     pub oob: bool,
-    pub screen_reader: bool
+    pub screen_reader: bool,
+    // Whether the client is buffering/editing input locally (LINEMODE EDIT)
+    // and trapping signal characters itself (LINEMODE TRAPSIG) rather than
+    // sending them raw for the server to interpret.
+    pub edit: bool,
+    pub trapsig: bool,
+    // Negotiated LINEMODE special characters (SLC), keyed by function code.
+    pub slc: HashMap<u8, u8>,
 }
 
 impl Default for TelnetConfig {
@@ -94,7 +342,10 @@ impl Default for TelnetConfig {
             width: 78,
             height: 24,
             oob: false,
-            screen_reader: false
+            screen_reader: false,
+            edit: false,
+            trapsig: false,
+            slc: Default::default(),
         }
     }
 }
@@ -116,6 +367,32 @@ impl TelnetHandshakes {
     }
 }
 
+// Error returned by `decompress_inbound`: either the zlib stream itself was
+// invalid, or decompressing it would have exceeded our decompression-bomb
+// guard (see `MCCP3_MAX_OUTPUT`).
+#[derive(Debug)]
+pub enum MccpError {
+    Decompress(DecompressError),
+    OutputTooLarge
+}
+
+impl std::fmt::Display for MccpError {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            MccpError::Decompress(e) => write!(f, "MCCP3 decompression failed: {}", e),
+            MccpError::OutputTooLarge => write!(f, "MCCP3 chunk exceeded the decompression output limit"),
+        }
+    }
+}
+
+impl std::error::Error for MccpError {}
+
+impl From<DecompressError> for MccpError {
+    fn from(e: DecompressError) -> Self {
+        MccpError::Decompress(e)
+    }
+}
+
 pub struct MuTelnet {
     op_state: HashMap<u8, TelnetOptionState>,
     config: TelnetConfig,
@@ -123,7 +400,12 @@ pub struct MuTelnet {
     ttype_count: u8,
     ttype_last: Option<String>,
     telnet_options: Arc<HashMap<u8, TelnetOption>>,
-    pub send_events: Vec<TelnetEvent>
+    pub send_events: Vec<TelnetEvent>,
+    pub received_gmcp: Vec<(String, Value)>,
+    pub received_msdp: Vec<(String, MsdpValue)>,
+    mssp_vars: HashMap<String, Vec<String>>,
+    mccp2: Option<Compress>,
+    mccp3: Option<Decompress>
 
 }
 
@@ -136,37 +418,34 @@ impl MuTelnet {
             ttype_count: 0,
             ttype_last: None,
             telnet_options,
-            send_events: Default::default()
+            send_events: Default::default(),
+            received_gmcp: Default::default(),
+            received_msdp: Default::default(),
+            mssp_vars: Default::default(),
+            mccp2: None,
+            mccp3: None
         }
     }
 
+    // Sets (or replaces) the values reported for an MSSP variable, e.g.
+    // `set_mssp_var("NAME", vec!["MyMud".to_string()])`.
+    pub fn set_mssp_var(&mut self, name: &str, values: Vec<String>) {
+        self.mssp_vars.insert(name.to_string(), values);
+    }
+
     pub fn start(&mut self) {
-        let mut start_local = HashSet::new();
-        let mut start_remote = HashSet::new();
+        let options = Arc::clone(&self.telnet_options);
 
-        for (op, option) in self.telnet_options.iter() {
+        for (op, option) in options.iter() {
             self.op_state.insert(*op, TelnetOptionState::default());
-            if let Some(state) = self.op_state.get_mut(op) {
-                if option.start_remote {
-                    start_remote.insert(*op);
-                    self.handshakes_left.remote.insert(*op);
-                    state.remote.negotiating = true;
-                }
-                if option.start_local {
-                    start_local.insert(*op);
-                    self.handshakes_left.local.insert(*op);
-                    state.local.negotiating = true;
-                }
-
+            if option.start_local {
+                self.handshakes_left.local.insert(*op);
+                self.request_local_enable(*op);
+            }
+            if option.start_remote {
+                self.handshakes_left.remote.insert(*op);
+                self.request_remote_enable(*op);
             }
-        }
-
-        for op in start_local {
-            self.send_events.push(TelnetEvent::Negotiate(WILL, op));
-        }
-
-        for op in start_remote {
-            self.send_events.push(TelnetEvent::Negotiate(DO, op));
         }
     }
 
@@ -188,124 +467,325 @@ impl MuTelnet {
         self.send_events.push(TelnetEvent::Data(Bytes::from(cleaned)));
     }
 
+    // Sends a prompt without forcing a trailing newline. If the client has
+    // negotiated TELOPT_EOR we end the prompt with IAC EOR; otherwise, as
+    // long as we haven't suppressed go-ahead, IAC GA. Only when neither is
+    // available do we fall back to a bare newline so the prompt at least
+    // isn't stuck on the same line as the next output.
+    //
+    // We read `local_enabled(TELOPT_EOR)` straight from `op_state` rather
+    // than mirroring it into `TelnetConfig`: the Q Method state machine is
+    // already the single source of truth for negotiated options. EOR has no
+    // arm in `enable_local`/`disable_local` and SGA's arms there are empty,
+    // because nothing needs to react when they flip — `send_prompt` just
+    // wants to know the current state.
     pub fn send_prompt(&mut self, in_str: &str) {
-        // TODO: Add proper prompt handling.
-        self.send_line(in_str);
+        let cleaned = Self::format_string(in_str);
+        self.send_events.push(TelnetEvent::Data(Bytes::from(cleaned)));
+
+        if self.local_enabled(TELOPT_EOR) {
+            self.send_events.push(TelnetEvent::Command(EOR));
+        } else if !self.local_enabled(SGA) {
+            self.send_events.push(TelnetEvent::Command(GA));
+        } else {
+            self.send_events.push(TelnetEvent::Data(Bytes::from_static(b"\r\n")));
+        }
     }
 
-    pub fn receive_negotiate(&mut self, command: u8, op: u8) -> bool {
-        // This means we received an IAC will/wont/do/dont...
-        // This function returns true/false depending on if its Config changed.
-        let mut handshake: u8 = 0;
-        let mut enable_local = false;
-        let mut disable_local = false;
-        let mut enable_remote = false;
-        let mut disable_remote = false;
-        let mut handshake_remote: u8 = 0;
-        let mut handshake_local: u8 = 0;
-        let mut respond: u8 = 0;
-
-        if let Some(state) = self.op_state.get_mut(&op) {
-            // We DO have a handler for this option... that means we support it!
-
-            match command {
-                WILL => {
-                    // The remote host has sent a WILL. They either want to Locally-Enable op, or are
-                    // doing so at our request.
-                    if !state.remote.enabled {
-                        if state.remote.negotiating {
-                            state.remote.negotiating = false;
-                        }
-                        else {
-                            respond = DO;
-                        }
-                        handshake = op;
-                        handshake_remote = op;
-                        enable_remote = true;
-                        state.remote.enabled = true;
-                    }
-                },
-                WONT => {
-                    // The client has refused an option we wanted to enable. Alternatively, it has
-                    // disabled an option that was on.
-                    if state.remote.negotiating {
-                        handshake = op;
-                        handshake_remote = op;
-                    }
-                    state.remote.negotiating = false;
-                    if state.remote.enabled {
-                        disable_remote = true;
-                        state.remote.enabled = false;
-                    }
-                },
-                DO => {
-                    // The client wants the Server to enable Option, or they are acknowledging our
-                    // desire to do so.
-                    if !state.local.enabled {
-                        if state.local.negotiating {
-                            state.local.negotiating = false;
-                        }
-                        else {
-                            respond = WILL;
-                        }
-                        handshake = op;
-                        handshake_local = op;
-                        enable_local = true;
-                        state.local.enabled = true;
-                    }
-                },
-                DONT => {
-                    // The client wants the server to disable Option, or are they are refusing our
-                    // desire to do so.
-                    if state.local.negotiating {
-                        handshake = op;
-                        handshake_local = op;
-                    }
-                    state.local.negotiating = false;
-                    if state.local.enabled {
-                        disable_local = true;
-                        state.local.enabled = false
-                    }
-                },
-                _ => {
-                    // This cannot actually happen.
-                }
+    // Ask to enable an option we control (local). Mirrors the "request
+    // enable" half of the RFC 1143 Q Method for the local side.
+    fn request_local_enable(&mut self, op: u8) {
+        let current = match self.op_state.get(&op) {
+            Some(s) => (s.local.state, s.local.queue),
+            None => return,
+        };
+        match current {
+            (QState::No, _) => {
+                self.set_local(op, QState::WantYes, QQueue::Empty);
+                self.send_events.push(TelnetEvent::Negotiate(WILL, op));
+            }
+            (QState::Yes, _) => {}
+            (QState::WantNo, QQueue::Empty) => {
+                self.set_local_queue(op, QQueue::Opposite);
+            }
+            (QState::WantNo, QQueue::Opposite) => {}
+            (QState::WantYes, QQueue::Empty) => {}
+            (QState::WantYes, QQueue::Opposite) => {
+                self.set_local_queue(op, QQueue::Empty);
+            }
+        }
+    }
+
+    // Ask to disable an option we control (local), e.g. to turn GMCP back
+    // off at runtime after having enabled it. Mirrors the "request disable"
+    // half of the RFC 1143 Q Method for the local side.
+    pub fn request_local_disable(&mut self, op: u8) {
+        let current = match self.op_state.get(&op) {
+            Some(s) => (s.local.state, s.local.queue),
+            None => return,
+        };
+        match current {
+            (QState::No, _) => {}
+            (QState::Yes, _) => {
+                self.set_local(op, QState::WantNo, QQueue::Empty);
+                self.send_events.push(TelnetEvent::Negotiate(WONT, op));
+            }
+            (QState::WantNo, QQueue::Empty) => {}
+            (QState::WantNo, QQueue::Opposite) => {
+                self.set_local_queue(op, QQueue::Empty);
+            }
+            (QState::WantYes, QQueue::Empty) => {
+                self.set_local_queue(op, QQueue::Opposite);
+            }
+            (QState::WantYes, QQueue::Opposite) => {}
+        }
+    }
+
+    // Ask the peer to enable an option on itself (remote).
+    fn request_remote_enable(&mut self, op: u8) {
+        let current = match self.op_state.get(&op) {
+            Some(s) => (s.remote.state, s.remote.queue),
+            None => return,
+        };
+        match current {
+            (QState::No, _) => {
+                self.set_remote(op, QState::WantYes, QQueue::Empty);
+                self.send_events.push(TelnetEvent::Negotiate(DO, op));
             }
+            (QState::Yes, _) => {}
+            (QState::WantNo, QQueue::Empty) => {
+                self.set_remote_queue(op, QQueue::Opposite);
+            }
+            (QState::WantNo, QQueue::Opposite) => {}
+            (QState::WantYes, QQueue::Empty) => {}
+            (QState::WantYes, QQueue::Opposite) => {
+                self.set_remote_queue(op, QQueue::Empty);
+            }
+        }
+    }
+
+    // Ask the peer to disable an option on itself (remote), e.g. to revoke
+    // NAWS once the server no longer needs window-size updates.
+    pub fn request_remote_disable(&mut self, op: u8) {
+        let current = match self.op_state.get(&op) {
+            Some(s) => (s.remote.state, s.remote.queue),
+            None => return,
+        };
+        match current {
+            (QState::No, _) => {}
+            (QState::Yes, _) => {
+                self.set_remote(op, QState::WantNo, QQueue::Empty);
+                self.send_events.push(TelnetEvent::Negotiate(DONT, op));
+            }
+            (QState::WantNo, QQueue::Empty) => {}
+            (QState::WantNo, QQueue::Opposite) => {
+                self.set_remote_queue(op, QQueue::Empty);
+            }
+            (QState::WantYes, QQueue::Empty) => {
+                self.set_remote_queue(op, QQueue::Opposite);
+            }
+            (QState::WantYes, QQueue::Opposite) => {}
+        }
+    }
+
+    // Set the local state, firing enable_local/disable_local when the
+    // settled (YES) status actually flips. Returns whether config changed.
+    fn set_local(&mut self, op: u8, new_state: QState, new_queue: QQueue) -> bool {
+        let was_yes = self.op_state.get(&op).map(|s| s.local.state == QState::Yes).unwrap_or(false);
+        if let Some(s) = self.op_state.get_mut(&op) {
+            s.local.state = new_state;
+            s.local.queue = new_queue;
+        }
+        let is_yes = new_state == QState::Yes;
+        if !was_yes && is_yes {
+            self.enable_local(op)
+        } else if was_yes && !is_yes {
+            self.disable_local(op)
         } else {
-            // We do not have a handler for this option, whatever it is... do not support.
-            respond = match command {
-                WILL => DONT,
-                DO => WONT,
-                _ => 0
-            };
+            false
         }
-        let mut changed: bool = false;
+    }
 
-        if respond > 0 {
-            self.send_events.push(TelnetEvent::Negotiate(respond, op));
+    fn set_local_queue(&mut self, op: u8, queue: QQueue) {
+        if let Some(s) = self.op_state.get_mut(&op) {
+            s.local.queue = queue;
         }
-        if handshake_local > 0 {
-            self.handshakes_left.local.remove(&handshake_local);
+    }
+
+    // Set the remote state, firing enable_remote/disable_remote when the
+    // settled (YES) status actually flips. Returns whether config changed.
+    fn set_remote(&mut self, op: u8, new_state: QState, new_queue: QQueue) -> bool {
+        let was_yes = self.op_state.get(&op).map(|s| s.remote.state == QState::Yes).unwrap_or(false);
+        if let Some(s) = self.op_state.get_mut(&op) {
+            s.remote.state = new_state;
+            s.remote.queue = new_queue;
         }
-        if handshake_remote > 0 {
-            self.handshakes_left.remote.remove(&handshake_remote);
+        let is_yes = new_state == QState::Yes;
+        if !was_yes && is_yes {
+            self.enable_remote(op)
+        } else if was_yes && !is_yes {
+            self.disable_remote(op)
+        } else {
+            false
         }
-        if enable_local {
-            changed = self.enable_local(op);
+    }
+
+    fn set_remote_queue(&mut self, op: u8, queue: QQueue) {
+        if let Some(s) = self.op_state.get_mut(&op) {
+            s.remote.queue = queue;
+        }
+    }
+
+    fn local_enabled(&self, op: u8) -> bool {
+        self.op_state.get(&op).map(|s| s.local.is_enabled()).unwrap_or(false)
+    }
+
+    fn remote_enabled(&self, op: u8) -> bool {
+        self.op_state.get(&op).map(|s| s.remote.is_enabled()).unwrap_or(false)
+    }
+
+    pub fn receive_negotiate(&mut self, command: u8, op: u8) -> bool {
+        // This means we received an IAC will/wont/do/dont...
+        // This function returns true/false depending on if its Config changed.
+        if !self.op_state.contains_key(&op) {
+            // We do not have a handler for this option, whatever it is... do not support.
+            let respond = match command {
+                WILL => Some(DONT),
+                DO => Some(WONT),
+                _ => None,
+            };
+            if let Some(respond) = respond {
+                self.send_events.push(TelnetEvent::Negotiate(respond, op));
+            }
+            return false;
         }
-        if disable_local {
-            changed = self.disable_local(op);
+
+        match command {
+            WILL => self.receive_will(op),
+            WONT => self.receive_wont(op),
+            DO => self.receive_do(op),
+            DONT => self.receive_dont(op),
+            _ => false,
         }
-        if enable_remote {
-            changed = self.enable_remote(op);
+    }
+
+    // The remote host has sent WILL: it wants to locally-enable `op` on its
+    // side, or is answering a DO we sent earlier.
+    fn receive_will(&mut self, op: u8) -> bool {
+        self.handshakes_left.remote.remove(&op);
+        let allow = self.telnet_options.get(&op).map(|o| o.allow_remote).unwrap_or(false);
+        let current = match self.op_state.get(&op) {
+            Some(s) => (s.remote.state, s.remote.queue),
+            None => return false,
+        };
+        match current {
+            (QState::No, _) => {
+                if allow {
+                    self.send_events.push(TelnetEvent::Negotiate(DO, op));
+                    self.set_remote(op, QState::Yes, QQueue::Empty)
+                } else {
+                    self.send_events.push(TelnetEvent::Negotiate(DONT, op));
+                    false
+                }
+            }
+            (QState::Yes, _) => false,
+            (QState::WantNo, QQueue::Empty) => {
+                // Error: our DONT should be answered by WONT, not WILL. Accept it anyway.
+                self.set_remote(op, QState::No, QQueue::Empty)
+            }
+            (QState::WantNo, QQueue::Opposite) => {
+                self.set_remote(op, QState::Yes, QQueue::Empty)
+            }
+            (QState::WantYes, QQueue::Empty) => {
+                self.set_remote(op, QState::Yes, QQueue::Empty)
+            }
+            (QState::WantYes, QQueue::Opposite) => {
+                self.send_events.push(TelnetEvent::Negotiate(DONT, op));
+                self.set_remote(op, QState::WantNo, QQueue::Empty)
+            }
         }
-        if disable_remote {
-            changed = self.disable_remote(op);
+    }
+
+    // The remote host has sent WONT: it refuses (or disables) `op` on its side.
+    fn receive_wont(&mut self, op: u8) -> bool {
+        self.handshakes_left.remote.remove(&op);
+        let current = match self.op_state.get(&op) {
+            Some(s) => (s.remote.state, s.remote.queue),
+            None => return false,
+        };
+        match current {
+            (QState::No, _) => false,
+            (QState::Yes, _) => {
+                self.send_events.push(TelnetEvent::Negotiate(DONT, op));
+                self.set_remote(op, QState::No, QQueue::Empty)
+            }
+            (QState::WantNo, QQueue::Empty) => self.set_remote(op, QState::No, QQueue::Empty),
+            (QState::WantNo, QQueue::Opposite) => {
+                self.send_events.push(TelnetEvent::Negotiate(DO, op));
+                self.set_remote(op, QState::WantYes, QQueue::Empty)
+            }
+            (QState::WantYes, _) => self.set_remote(op, QState::No, QQueue::Empty),
         }
-        if handshake > 0 {
-            //self.check_ready();
+    }
+
+    // The remote host has sent DO: it wants the server to enable `op`
+    // locally, or is answering a WILL we sent earlier.
+    fn receive_do(&mut self, op: u8) -> bool {
+        self.handshakes_left.local.remove(&op);
+        let allow = self.telnet_options.get(&op).map(|o| o.allow_local).unwrap_or(false);
+        let current = match self.op_state.get(&op) {
+            Some(s) => (s.local.state, s.local.queue),
+            None => return false,
+        };
+        match current {
+            (QState::No, _) => {
+                if allow {
+                    self.send_events.push(TelnetEvent::Negotiate(WILL, op));
+                    self.set_local(op, QState::Yes, QQueue::Empty)
+                } else {
+                    self.send_events.push(TelnetEvent::Negotiate(WONT, op));
+                    false
+                }
+            }
+            (QState::Yes, _) => false,
+            (QState::WantNo, QQueue::Empty) => {
+                // Error: our WONT should be answered by DONT, not DO. Accept it anyway.
+                self.set_local(op, QState::No, QQueue::Empty)
+            }
+            (QState::WantNo, QQueue::Opposite) => {
+                self.set_local(op, QState::Yes, QQueue::Empty)
+            }
+            (QState::WantYes, QQueue::Empty) => {
+                self.set_local(op, QState::Yes, QQueue::Empty)
+            }
+            (QState::WantYes, QQueue::Opposite) => {
+                self.send_events.push(TelnetEvent::Negotiate(WONT, op));
+                self.set_local(op, QState::WantNo, QQueue::Empty)
+            }
+        }
+    }
+
+    // The remote host has sent DONT: it wants the server to disable `op`
+    // locally, or is refusing a WILL we sent earlier.
+    fn receive_dont(&mut self, op: u8) -> bool {
+        self.handshakes_left.local.remove(&op);
+        let current = match self.op_state.get(&op) {
+            Some(s) => (s.local.state, s.local.queue),
+            None => return false,
+        };
+        match current {
+            (QState::No, _) => false,
+            (QState::Yes, _) => {
+                self.send_events.push(TelnetEvent::Negotiate(WONT, op));
+                self.set_local(op, QState::No, QQueue::Empty)
+            }
+            (QState::WantNo, QQueue::Empty) => self.set_local(op, QState::No, QQueue::Empty),
+            (QState::WantNo, QQueue::Opposite) => {
+                self.send_events.push(TelnetEvent::Negotiate(WILL, op));
+                self.set_local(op, QState::WantYes, QQueue::Empty)
+            }
+            (QState::WantYes, _) => self.set_local(op, QState::No, QQueue::Empty),
         }
-        changed
     }
 
     fn enable_remote(&mut self, op: u8) -> bool {
@@ -314,7 +794,13 @@ impl MuTelnet {
             TTYPE => {
                 self.request_ttype();
             },
-            //LINEMODE => self.config.linemode = true,
+            LINEMODE => {
+                self.send_linemode_mode();
+            },
+            MCCP3 => {
+                // Decompression only actually begins once the client sends
+                // its start marker; see `handle_sub`.
+            },
             _ => {
                 // Whatever this option is.. well, whatever.
             }
@@ -334,7 +820,15 @@ impl MuTelnet {
                 //self.config.ttype = false;
                 self.handshakes_left.ttype.clear();
             },
-            //LINEMODE => self.config.linemode = false,
+            LINEMODE => {
+                self.config.edit = false;
+                self.config.trapsig = false;
+                self.config.slc.clear();
+                return true;
+            },
+            MCCP3 => {
+                self.mccp3 = None;
+            },
             _ => {
                 // Whatever this option is.. well, whatever.
             }
@@ -347,6 +841,16 @@ impl MuTelnet {
             SGA => {
                 //self.config.sga = true;
             },
+            MSSP => {
+                self.send_mssp();
+            },
+            MCCP2 => {
+                self.send_events.push(TelnetEvent::SubNegotiate(MCCP2, Bytes::new()));
+                self.mccp2 = Some(Compress::new(Compression::default(), true));
+            },
+            CHARSET => {
+                self.send_charset_request();
+            },
             _ => {
 
             }
@@ -359,6 +863,13 @@ impl MuTelnet {
             SGA => {
                 //self.config.sga = false;
             },
+            MCCP2 => {
+                self.mccp2 = None;
+            },
+            CHARSET => {
+                self.config.encoding = "ascii".to_string();
+                return true;
+            },
             _ => {
 
             }
@@ -381,11 +892,411 @@ impl MuTelnet {
             TTYPE => {
                 changed = self.receive_ttype(data);
             }
+            GMCP => {
+                // Queues the message in `received_gmcp`; config is untouched.
+                self.receive_gmcp(data);
+            }
+            MSDP => {
+                // Queues the values in `received_msdp`; config is untouched.
+                self.receive_msdp(data);
+            }
+            MSSP => {
+                // Some crawlers send a bare MSSP subnegotiation instead of
+                // waiting for the DO/WILL handshake to finish; answer either way.
+                self.send_mssp();
+            }
+            LINEMODE => {
+                changed = self.receive_linemode(data);
+            }
+            CHARSET => {
+                changed = self.receive_charset(data);
+            }
+            MCCP3 => {
+                // The client's start marker; everything it sends after this
+                // is zlib-compressed. Only honor it once the peer has
+                // actually been granted MCCP3, so a client can't force us
+                // into "expect compressed input" mode unsolicited.
+                if self.remote_enabled(MCCP3) {
+                    self.mccp3 = Some(Decompress::new(true));
+                }
+            }
             _ => {}
         }
         changed
     }
 
+    fn receive_gmcp(&mut self, data: Bytes) -> bool {
+        if !self.local_enabled(GMCP) {
+            return false;
+        }
+
+        let s = match String::from_utf8(data.to_vec()) {
+            Ok(s) => s,
+            Err(_) => return false,
+        };
+
+        let (package, rest) = match s.find(' ') {
+            Some(idx) => (s[..idx].to_string(), s[idx + 1..].trim()),
+            None => (s.trim().to_string(), ""),
+        };
+
+        let value = if rest.is_empty() {
+            Value::Null
+        } else {
+            match serde_json::from_str(rest) {
+                Ok(value) => value,
+                Err(_) => return false,
+            }
+        };
+
+        self.received_gmcp.push((package, value));
+        true
+    }
+
+    // Sends a GMCP message as `Package.SubPackage <json>`. No-op unless the
+    // peer has negotiated GMCP enabled.
+    pub fn send_gmcp(&mut self, package: &str, value: &Value) {
+        if !self.local_enabled(GMCP) {
+            return;
+        }
+
+        let mut payload = String::from(package);
+        if !value.is_null() {
+            payload.push(' ');
+            payload.push_str(&value.to_string());
+        }
+        self.send_events.push(TelnetEvent::SubNegotiate(GMCP, Bytes::from(payload)));
+    }
+
+    fn receive_msdp(&mut self, data: Bytes) -> bool {
+        if !self.local_enabled(MSDP) {
+            return false;
+        }
+
+        match decode_msdp(&data) {
+            Some(pairs) => {
+                let changed = !pairs.is_empty();
+                self.received_msdp.extend(pairs);
+                changed
+            }
+            None => false, // malformed stream; drop it rather than panic
+        }
+    }
+
+    // Sends a set of MSDP variables. No-op unless the peer has negotiated
+    // MSDP enabled.
+    pub fn send_msdp(&mut self, pairs: &[(String, MsdpValue)]) {
+        if !self.local_enabled(MSDP) {
+            return;
+        }
+
+        self.send_events.push(TelnetEvent::SubNegotiate(MSDP, encode_msdp(pairs)));
+    }
+
+    // Sends the configured MSSP status variables. Called automatically once
+    // MSSP is locally enabled (or a crawler probes it directly), but can
+    // also be called again later if the status changes mid-session.
+    pub fn send_mssp(&mut self) {
+        self.send_events.push(TelnetEvent::SubNegotiate(MSSP, encode_mssp(&self.mssp_vars)));
+    }
+
+    // Requests the client switch its LINEMODE EDIT/TRAPSIG bits to match
+    // `config.edit`/`config.trapsig`. No-op unless LINEMODE is enabled.
+    pub fn set_linemode_mode(&mut self, edit: bool, trapsig: bool) {
+        if !self.remote_enabled(LINEMODE) {
+            return;
+        }
+        self.config.edit = edit;
+        self.config.trapsig = trapsig;
+        self.send_linemode_mode();
+    }
+
+    fn send_linemode_mode(&mut self) {
+        let mut mode = 0u8;
+        if self.config.edit {
+            mode |= LINEMODE_MODE_EDIT;
+        }
+        if self.config.trapsig {
+            mode |= LINEMODE_MODE_TRAPSIG;
+        }
+        self.send_events.push(TelnetEvent::SubNegotiate(LINEMODE, Bytes::from(vec![LINEMODE_MODE, mode])));
+    }
+
+    fn receive_linemode(&mut self, mut data: Bytes) -> bool {
+        if !self.remote_enabled(LINEMODE) {
+            return false;
+        }
+        if data.is_empty() {
+            return false;
+        }
+        let sub = data[0];
+        data.advance(1);
+        match sub {
+            LINEMODE_MODE => self.receive_linemode_mode(data),
+            LINEMODE_SLC => self.receive_slc(data),
+            _ => false,
+        }
+    }
+
+    fn receive_linemode_mode(&mut self, data: Bytes) -> bool {
+        if data.is_empty() {
+            return false;
+        }
+        let mode = data[0];
+        let edit = (mode & LINEMODE_MODE_EDIT) != 0;
+        let trapsig = (mode & LINEMODE_MODE_TRAPSIG) != 0;
+        let changed = edit != self.config.edit || trapsig != self.config.trapsig;
+        self.config.edit = edit;
+        self.config.trapsig = trapsig;
+        changed
+    }
+
+    // Our own "default" value for an SLC function, sent back when the
+    // client asks us with SLC_DEFAULT.
+    fn default_slc_value(&self, func: u8) -> u8 {
+        match func {
+            SLC_EOF => 4,   // Ctrl-D
+            SLC_SUSP => 26, // Ctrl-Z
+            SLC_EC => 127,  // DEL (erase character)
+            SLC_EL => 21,   // Ctrl-U (erase line / "kill")
+            _ => 0,
+        }
+    }
+
+    // Whether `func` is one of the SLC functions we actually track.
+    fn is_known_slc(func: u8) -> bool {
+        matches!(func, SLC_EOF | SLC_SUSP | SLC_EC | SLC_EL)
+    }
+
+    // Processes a sequence of (func, modifier, value) SLC triples, following
+    // the RFC 1184 level rules, and replies with our own triples for any
+    // function where the client asked us to pick (SLC_DEFAULT) or proposed a
+    // value we're acknowledging (SLC_VALUE).
+    fn receive_slc(&mut self, data: Bytes) -> bool {
+        let mut changed = false;
+        let mut reply = Vec::new();
+        let mut i = 0;
+
+        while i + 3 <= data.len() {
+            let func = data[i];
+            let modifier = data[i + 1];
+            let value = data[i + 2];
+            i += 3;
+
+            let level = modifier & SLC_LEVEL_MASK;
+            let flags = modifier & !SLC_LEVEL_MASK;
+
+            match level {
+                SLC_DEFAULT => {
+                    if Self::is_known_slc(func) {
+                        let our_value = self.default_slc_value(func);
+                        self.config.slc.insert(func, our_value);
+                        reply.push(func);
+                        reply.push(SLC_VALUE | flags);
+                        reply.push(our_value);
+                    } else {
+                        // We don't track this function; say so rather than
+                        // fabricating a value we'll never honor.
+                        self.config.slc.remove(&func);
+                        reply.push(func);
+                        reply.push(SLC_NOSUPPORT);
+                        reply.push(0);
+                    }
+                    changed = true;
+                }
+                SLC_VALUE => {
+                    if flags & SLC_ACK != 0 {
+                        // The client is just acking a value we proposed
+                        // earlier; record it without re-acking, or we'd
+                        // ping-pong with a client that acks symmetrically.
+                        changed = self.config.slc.insert(func, value) != Some(value) || changed;
+                    } else if Self::is_known_slc(func) {
+                        // We have no conflicting preference, so accept it as-is.
+                        self.config.slc.insert(func, value);
+                        reply.push(func);
+                        reply.push(modifier | SLC_ACK);
+                        reply.push(value);
+                        changed = true;
+                    } else {
+                        // We don't track this function; say so rather than
+                        // agreeing to a value we'll never honor.
+                        self.config.slc.remove(&func);
+                        reply.push(func);
+                        reply.push(SLC_NOSUPPORT);
+                        reply.push(0);
+                        changed = true;
+                    }
+                }
+                SLC_CANTCHANGE => {
+                    // The client won't let this one change; record what it has.
+                    self.config.slc.insert(func, value);
+                    changed = true;
+                }
+                SLC_NOSUPPORT => {
+                    self.config.slc.remove(&func);
+                    changed = true;
+                }
+                _ => {}
+            }
+        }
+
+        if !reply.is_empty() {
+            let mut payload = Vec::with_capacity(reply.len() + 1);
+            payload.push(LINEMODE_SLC);
+            payload.extend(reply);
+            self.send_events.push(TelnetEvent::SubNegotiate(LINEMODE, Bytes::from(payload)));
+        }
+
+        changed
+    }
+
+    fn send_charset_request(&mut self) {
+        let mut payload = vec![CHARSET_REQUEST];
+        for charset in SUPPORTED_CHARSETS {
+            payload.push(CHARSET_SEP);
+            payload.extend_from_slice(charset.as_bytes());
+        }
+        self.send_events.push(TelnetEvent::SubNegotiate(CHARSET, Bytes::from(payload)));
+    }
+
+    fn receive_charset(&mut self, data: Bytes) -> bool {
+        if !self.local_enabled(CHARSET) {
+            return false;
+        }
+        if data.is_empty() {
+            return false;
+        }
+        let sub = data[0];
+        let rest = data.slice(1..);
+        match sub {
+            CHARSET_REQUEST => self.receive_charset_request(rest),
+            CHARSET_ACCEPTED => self.receive_charset_accepted(rest),
+            CHARSET_REJECTED | CHARSET_TTABLE_IS => false,
+            _ => false,
+        }
+    }
+
+    // The peer accepted one of the charsets we offered.
+    fn receive_charset_accepted(&mut self, data: Bytes) -> bool {
+        match String::from_utf8(data.to_vec()) {
+            Ok(name) => match Self::canonical_charset(&name) {
+                Some(canonical) => {
+                    let changed = self.config.encoding != canonical;
+                    self.config.encoding = canonical;
+                    changed
+                }
+                None => false,
+            },
+            Err(_) => false,
+        }
+    }
+
+    // Maps a charset name (in whatever casing the peer used) to our
+    // canonical `config.encoding` spelling, matching the lowercase/no-hyphen
+    // convention already used for "ascii" and "utf8" elsewhere in this file.
+    // US-ASCII is special-cased to "ascii" (rather than "usascii") so a
+    // negotiated US-ASCII matches the default/disabled encoding value.
+    fn canonical_charset(name: &str) -> Option<String> {
+        SUPPORTED_CHARSETS
+            .iter()
+            .find(|supported| supported.eq_ignore_ascii_case(name))
+            .map(|supported| {
+                if supported.eq_ignore_ascii_case("US-ASCII") {
+                    "ascii".to_string()
+                } else {
+                    supported.to_ascii_lowercase().replace('-', "")
+                }
+            })
+    }
+
+    // The peer is requesting we pick a charset from its list; answer with
+    // the first one we also support, or REJECTED if none match.
+    fn receive_charset_request(&mut self, data: Bytes) -> bool {
+        if data.is_empty() {
+            return false;
+        }
+        let sep = data[0];
+        let offered: Vec<&str> = data[1..]
+            .split(|b| *b == sep)
+            .filter_map(|chunk| std::str::from_utf8(chunk).ok())
+            .filter(|s| !s.is_empty())
+            .collect();
+
+        let picked = offered.iter().find_map(|name| Self::canonical_charset(name).map(|canonical| (*name, canonical)));
+
+        match picked {
+            Some((name, canonical)) => {
+                let mut payload = vec![CHARSET_ACCEPTED];
+                payload.extend_from_slice(name.as_bytes());
+                self.send_events.push(TelnetEvent::SubNegotiate(CHARSET, Bytes::from(payload)));
+                let changed = self.config.encoding != canonical;
+                self.config.encoding = canonical;
+                changed
+            }
+            None => {
+                self.send_events.push(TelnetEvent::SubNegotiate(CHARSET, Bytes::from(vec![CHARSET_REJECTED])));
+                false
+            }
+        }
+    }
+
+    // Compresses outbound bytes through the MCCP2 stream once it's been
+    // switched on (after the start marker); otherwise passes them through
+    // unchanged. The surrounding I/O loop calls this on each `Data` event's
+    // payload before writing it to the socket.
+    //
+    // `compress_vec` only ever fills the vec's existing spare capacity; it
+    // doesn't grow the vec itself, and a flush that runs out of room
+    // returns with the vec full and the rest of its output still pending
+    // inside the compressor. A single call sized off `data.len() + 32` is
+    // enough for most traffic but not for large or poorly-compressible
+    // payloads, so keep growing the buffer and calling back in until every
+    // input byte has been consumed and a call returns with spare capacity
+    // left, i.e. the flush has fully drained.
+    pub fn compress_outbound(&mut self, data: Bytes) -> Bytes {
+        match &mut self.mccp2 {
+            Some(compress) => {
+                let mut out = Vec::with_capacity(data.len() + 32);
+                let mut input = &data[..];
+                loop {
+                    let before_in = compress.total_in();
+                    out.reserve(data.len().max(32));
+                    compress
+                        .compress_vec(input, &mut out, FlushCompress::Sync)
+                        .expect("zlib compression of our own outbound stream should not fail");
+                    let consumed = (compress.total_in() - before_in) as usize;
+                    input = &input[consumed..];
+                    if input.is_empty() && out.len() < out.capacity() {
+                        break;
+                    }
+                }
+                Bytes::from(out)
+            }
+            None => data,
+        }
+    }
+
+    // Decompresses inbound bytes through the MCCP3 stream once the client
+    // has sent its start marker; otherwise passes them through unchanged.
+    // The surrounding I/O loop calls this on raw bytes read from the socket
+    // before handing them to the telnet parser.
+    pub fn decompress_inbound(&mut self, data: Bytes) -> Result<Bytes, MccpError> {
+        match &mut self.mccp3 {
+            Some(decompress) => {
+                let cap = (data.len() * MCCP3_MAX_RATIO)
+                    .min(MCCP3_MAX_OUTPUT)
+                    .max(data.len() + 32);
+                let mut out = Vec::with_capacity(cap);
+                decompress.decompress_vec(&data, &mut out, FlushDecompress::Sync)?;
+                if out.len() >= cap {
+                    return Err(MccpError::OutputTooLarge);
+                }
+                Ok(Bytes::from(out))
+            }
+            None => Ok(data),
+        }
+    }
+
     fn request_ttype(&mut self) {
         let mut data = BytesMut::with_capacity(1);
         data.put_u8(1);
@@ -562,8 +1473,498 @@ impl MuTelnet {
 
 #[cfg(test)]
 mod tests {
+    use super::*;
+
     #[test]
     fn it_works() {
         assert_eq!(2 + 2, 4);
     }
+
+    #[test]
+    fn simultaneous_will_and_do_for_same_op_settle_without_extra_negotiate() {
+        let options: Arc<HashMap<u8, TelnetOption>> = Arc::new(HashMap::new());
+        let mut telnet = MuTelnet::new(options);
+        telnet.op_state.insert(MCCP2, TelnetOptionState::default());
+
+        // We want MCCP2 enabled in both directions at once: WILL for our
+        // local side, DO for the peer's remote side.
+        telnet.request_local_enable(MCCP2);
+        telnet.request_remote_enable(MCCP2);
+
+        match telnet.send_events.first() {
+            Some(TelnetEvent::Negotiate(cmd, op)) => {
+                assert_eq!(*cmd, WILL);
+                assert_eq!(*op, MCCP2);
+            }
+            other => panic!("expected an outgoing WILL, got {:?}", other.is_some()),
+        }
+        match telnet.send_events.get(1) {
+            Some(TelnetEvent::Negotiate(cmd, op)) => {
+                assert_eq!(*cmd, DO);
+                assert_eq!(*op, MCCP2);
+            }
+            other => panic!("expected an outgoing DO, got {:?}", other.is_some()),
+        }
+
+        // The peer answers both in the same burst: DO settles our WILL,
+        // WILL settles our DO. Per RFC 1143, settling a WANTYES/Empty state
+        // never re-sends the negotiation, so no new Negotiate should appear.
+        telnet.receive_negotiate(DO, MCCP2);
+        telnet.receive_negotiate(WILL, MCCP2);
+
+        assert!(telnet.local_enabled(MCCP2));
+        assert!(telnet.remote_enabled(MCCP2));
+        let negotiate_count = telnet.send_events.iter()
+            .filter(|e| matches!(e, TelnetEvent::Negotiate(_, _)))
+            .count();
+        assert_eq!(negotiate_count, 2);
+    }
+
+    #[test]
+    fn request_local_enable_cancels_a_pending_disable_wantyes_opposite_flip_flop() {
+        let options: Arc<HashMap<u8, TelnetOption>> = Arc::new(HashMap::new());
+        let mut telnet = MuTelnet::new(options);
+        telnet.op_state.insert(GMCP, TelnetOptionState::default());
+
+        telnet.request_local_enable(GMCP);
+        assert_eq!(telnet.op_state[&GMCP].local.state, QState::WantYes);
+        assert_eq!(telnet.op_state[&GMCP].local.queue, QQueue::Empty);
+        assert_eq!(telnet.send_events.len(), 1);
+
+        // Change our mind mid-flight: queue a disable while the WILL is
+        // still outstanding. No message is sent yet; it just queues.
+        telnet.request_local_disable(GMCP);
+        assert_eq!(telnet.op_state[&GMCP].local.state, QState::WantYes);
+        assert_eq!(telnet.op_state[&GMCP].local.queue, QQueue::Opposite);
+        assert_eq!(telnet.send_events.len(), 1);
+
+        // Flip back to wanting it enabled before the peer's reply arrives;
+        // this should cancel the queued disable rather than stacking up.
+        telnet.request_local_enable(GMCP);
+        assert_eq!(telnet.op_state[&GMCP].local.state, QState::WantYes);
+        assert_eq!(telnet.op_state[&GMCP].local.queue, QQueue::Empty);
+        assert_eq!(telnet.send_events.len(), 1);
+
+        // The peer's DO now settles the state exactly where we last wanted
+        // it: enabled, with no DONT ever having been sent.
+        telnet.receive_negotiate(DO, GMCP);
+        assert!(telnet.local_enabled(GMCP));
+        assert_eq!(telnet.send_events.len(), 1);
+    }
+
+    #[test]
+    fn enable_local_and_disable_local_fire_exactly_once_on_yes_transition() {
+        let options: Arc<HashMap<u8, TelnetOption>> = Arc::new(HashMap::new());
+        let mut telnet = MuTelnet::new(options);
+        telnet.op_state.insert(MCCP2, TelnetOptionState::default());
+
+        telnet.request_local_enable(MCCP2);
+        telnet.receive_negotiate(DO, MCCP2);
+
+        assert!(telnet.mccp2.is_some());
+        let starts = telnet.send_events.iter()
+            .filter(|e| matches!(e, TelnetEvent::SubNegotiate(op, _) if *op == MCCP2))
+            .count();
+        assert_eq!(starts, 1);
+
+        // A redundant DO from the peer (already Yes) must not re-fire
+        // enable_local and push a second start marker.
+        telnet.receive_negotiate(DO, MCCP2);
+        let starts = telnet.send_events.iter()
+            .filter(|e| matches!(e, TelnetEvent::SubNegotiate(op, _) if *op == MCCP2))
+            .count();
+        assert_eq!(starts, 1);
+
+        telnet.request_local_disable(MCCP2);
+        telnet.receive_negotiate(DONT, MCCP2);
+        assert!(telnet.mccp2.is_none());
+
+        // A redundant DONT (already No) must not attempt to disable again.
+        telnet.receive_negotiate(DONT, MCCP2);
+        assert!(telnet.mccp2.is_none());
+    }
+
+    // Shared fixture for the "last event was a SubNegotiate for this op"
+    // assertion that recurs across GMCP/MSSP/LINEMODE/CHARSET tests below.
+    fn expect_last_subnegotiate(events: &[TelnetEvent], label: &str) -> (u8, Bytes) {
+        match events.last() {
+            Some(TelnetEvent::SubNegotiate(op, payload)) => (*op, payload.clone()),
+            other => panic!("expected {}, got {:?}", label, other.is_some()),
+        }
+    }
+
+    // Same idea for the "last event was this bare IAC command" assertion.
+    fn expect_last_command(events: &[TelnetEvent], label: &str) -> u8 {
+        match events.last() {
+            Some(TelnetEvent::Command(cmd)) => *cmd,
+            other => panic!("expected {}, got {:?}", label, other.is_some()),
+        }
+    }
+
+    #[test]
+    fn gmcp_round_trips_package_and_json_value() {
+        let options: Arc<HashMap<u8, TelnetOption>> = Arc::new(HashMap::new());
+        let mut sender = MuTelnet::new(Arc::clone(&options));
+        sender.op_state.insert(GMCP, TelnetOptionState {
+            local: TelnetOptionPerspective { state: QState::Yes, queue: QQueue::Empty },
+            remote: TelnetOptionPerspective::default(),
+        });
+
+        sender.send_gmcp("Char.Vitals", &serde_json::json!({"hp": 100, "mp": 50}));
+
+        let (op, payload) = expect_last_subnegotiate(&sender.send_events, "a GMCP SubNegotiate");
+        assert_eq!(op, GMCP);
+
+        let mut receiver = MuTelnet::new(options);
+        receiver.op_state.insert(GMCP, TelnetOptionState {
+            local: TelnetOptionPerspective { state: QState::Yes, queue: QQueue::Empty },
+            remote: TelnetOptionPerspective::default(),
+        });
+
+        let changed = receiver.receive_gmcp(payload);
+
+        assert!(changed);
+        assert_eq!(
+            receiver.received_gmcp,
+            vec![("Char.Vitals".to_string(), serde_json::json!({"hp": 100, "mp": 50}))]
+        );
+    }
+
+    #[test]
+    fn gmcp_rejects_malformed_json_and_non_utf8() {
+        let options: Arc<HashMap<u8, TelnetOption>> = Arc::new(HashMap::new());
+        let mut telnet = MuTelnet::new(options);
+        telnet.op_state.insert(GMCP, TelnetOptionState {
+            local: TelnetOptionPerspective { state: QState::Yes, queue: QQueue::Empty },
+            remote: TelnetOptionPerspective::default(),
+        });
+
+        let changed = telnet.receive_gmcp(Bytes::from_static(b"Char.Vitals {not json}"));
+        assert!(!changed);
+        assert!(telnet.received_gmcp.is_empty());
+
+        let changed = telnet.receive_gmcp(Bytes::from_static(&[0xff, 0xfe, 0xfd]));
+        assert!(!changed);
+        assert!(telnet.received_gmcp.is_empty());
+    }
+
+    #[test]
+    fn msdp_round_trips_nested_tables_and_arrays() {
+        let mut room = HashMap::new();
+        room.insert("NAME".to_string(), MsdpValue::Str("Limbo".to_string()));
+        room.insert(
+            "EXITS".to_string(),
+            MsdpValue::Array(vec![MsdpValue::Str("north".to_string()), MsdpValue::Str("south".to_string())]),
+        );
+
+        let pairs = vec![("ROOM".to_string(), MsdpValue::Table(room))];
+        let encoded = encode_msdp(&pairs);
+        let decoded = decode_msdp(&encoded).expect("valid MSDP stream");
+
+        assert_eq!(decoded, pairs);
+    }
+
+    #[test]
+    fn msdp_decode_rejects_unterminated_table() {
+        let mut data = BytesMut::new();
+        data.put_u8(MSDP_VAR);
+        data.put_slice(b"ROOM");
+        data.put_u8(MSDP_VAL);
+        data.put_u8(MSDP_TABLE_OPEN);
+        data.put_u8(MSDP_VAR);
+        data.put_slice(b"NAME");
+        data.put_u8(MSDP_VAL);
+        data.put_slice(b"Limbo");
+        // missing MSDP_TABLE_CLOSE
+
+        assert_eq!(decode_msdp(&data.freeze()), None);
+    }
+
+    #[test]
+    fn encode_mssp_repeats_var_for_each_value() {
+        let mut vars = HashMap::new();
+        vars.insert("PLAYERS".to_string(), vec!["3".to_string()]);
+        vars.insert("CODEBASE".to_string(), vec!["MuTelnet".to_string(), "RFC 2066".to_string()]);
+
+        let encoded = encode_mssp(&vars);
+
+        let mut expected_players = BytesMut::new();
+        expected_players.put_u8(MSSP_VAR);
+        expected_players.put_slice(b"PLAYERS");
+        expected_players.put_u8(MSSP_VAL);
+        expected_players.put_slice(b"3");
+
+        let mut expected_codebase = BytesMut::new();
+        for value in ["MuTelnet", "RFC 2066"] {
+            expected_codebase.put_u8(MSSP_VAR);
+            expected_codebase.put_slice(b"CODEBASE");
+            expected_codebase.put_u8(MSSP_VAL);
+            expected_codebase.put_slice(value.as_bytes());
+        }
+
+        // HashMap iteration order isn't guaranteed, so check each variable's
+        // repeated-VAR encoding shows up somewhere in the output rather than
+        // asserting a single fixed byte sequence.
+        let encoded_slice = encoded.as_ref();
+        assert!(windows_contain(encoded_slice, expected_players.as_ref()));
+        assert!(windows_contain(encoded_slice, expected_codebase.as_ref()));
+    }
+
+    fn windows_contain(haystack: &[u8], needle: &[u8]) -> bool {
+        haystack.windows(needle.len()).any(|w| w == needle)
+    }
+
+    #[test]
+    fn send_mssp_emits_configured_vars() {
+        let options: Arc<HashMap<u8, TelnetOption>> = Arc::new(HashMap::new());
+        let mut telnet = MuTelnet::new(options);
+        telnet.set_mssp_var("NAME", vec!["MyMud".to_string()]);
+
+        telnet.send_mssp();
+
+        let (op, payload) = expect_last_subnegotiate(&telnet.send_events, "an MSSP SubNegotiate");
+        assert_eq!(op, MSSP);
+        let mut expected = BytesMut::new();
+        expected.put_u8(MSSP_VAR);
+        expected.put_slice(b"NAME");
+        expected.put_u8(MSSP_VAL);
+        expected.put_slice(b"MyMud");
+        assert_eq!(payload.as_ref(), expected.as_ref());
+    }
+
+    #[test]
+    fn mccp_round_trips_compressed_data() {
+        let options: Arc<HashMap<u8, TelnetOption>> = Arc::new(HashMap::new());
+        let mut sender = MuTelnet::new(Arc::clone(&options));
+        sender.mccp2 = Some(Compress::new(Compression::default(), true));
+
+        let mut receiver = MuTelnet::new(options);
+        receiver.mccp3 = Some(Decompress::new(true));
+
+        let original = Bytes::from_static(b"Hello from the MUD, over and over and over again.");
+        let compressed = sender.compress_outbound(original.clone());
+        let decompressed = receiver.decompress_inbound(compressed).expect("valid zlib stream");
+
+        assert_eq!(decompressed, original);
+    }
+
+    #[test]
+    fn mccp_round_trips_payload_spanning_multiple_deflate_blocks() {
+        let options: Arc<HashMap<u8, TelnetOption>> = Arc::new(HashMap::new());
+        let mut sender = MuTelnet::new(Arc::clone(&options));
+        sender.mccp2 = Some(Compress::new(Compression::default(), true));
+
+        let mut receiver = MuTelnet::new(options);
+        receiver.mccp3 = Some(Decompress::new(true));
+
+        // Poorly-compressible, larger than the `data.len() + 32` margin a
+        // single-shot buffer would have assumed was always enough; this is
+        // the shape of payload that used to come back truncated.
+        let mut lcg_state = 0x2545F4914F6CDD1Du64;
+        let original: Bytes = (0..200_000)
+            .map(|_| {
+                lcg_state = lcg_state.wrapping_mul(6364136223846793005).wrapping_add(1442695040888963407);
+                (lcg_state >> 33) as u8
+            })
+            .collect();
+
+        let compressed = sender.compress_outbound(original.clone());
+        let decompressed = receiver.decompress_inbound(compressed).expect("valid zlib stream");
+
+        assert_eq!(decompressed, original);
+    }
+
+    #[test]
+    fn mccp3_start_marker_is_ignored_before_being_negotiated() {
+        let options: Arc<HashMap<u8, TelnetOption>> = Arc::new(HashMap::new());
+        let mut telnet = MuTelnet::new(options);
+
+        let changed = telnet.handle_sub(MCCP3, Bytes::new());
+
+        assert!(!changed);
+        assert!(telnet.mccp3.is_none());
+    }
+
+    #[test]
+    fn linemode_sub_is_ignored_before_being_negotiated() {
+        let options: Arc<HashMap<u8, TelnetOption>> = Arc::new(HashMap::new());
+        let mut telnet = MuTelnet::new(options);
+
+        let changed = telnet.receive_linemode(Bytes::from_static(&[LINEMODE_SLC, SLC_EOF, SLC_DEFAULT, 0]));
+
+        assert!(!changed);
+        assert_eq!(telnet.config.slc.get(&SLC_EOF), None);
+        assert!(telnet.send_events.is_empty());
+    }
+
+    #[test]
+    fn slc_default_request_is_answered_with_our_value_and_recorded() {
+        let options: Arc<HashMap<u8, TelnetOption>> = Arc::new(HashMap::new());
+        let mut telnet = MuTelnet::new(options);
+
+        let changed = telnet.receive_slc(Bytes::from_static(&[SLC_EOF, SLC_DEFAULT, 0]));
+
+        assert!(changed);
+        assert_eq!(telnet.config.slc.get(&SLC_EOF), Some(&4));
+        let (op, payload) = expect_last_subnegotiate(&telnet.send_events, "a LINEMODE SLC reply");
+        assert_eq!(op, LINEMODE);
+        assert_eq!(payload.as_ref(), &[LINEMODE_SLC, SLC_EOF, SLC_VALUE, 4]);
+    }
+
+    #[test]
+    fn slc_default_for_unknown_function_is_declined() {
+        let options: Arc<HashMap<u8, TelnetOption>> = Arc::new(HashMap::new());
+        let mut telnet = MuTelnet::new(options);
+
+        let changed = telnet.receive_slc(Bytes::from_static(&[99, SLC_DEFAULT, 0]));
+
+        assert!(changed);
+        assert_eq!(telnet.config.slc.get(&99), None);
+        let (op, payload) = expect_last_subnegotiate(&telnet.send_events, "a LINEMODE SLC reply");
+        assert_eq!(op, LINEMODE);
+        assert_eq!(payload.as_ref(), &[LINEMODE_SLC, 99, SLC_NOSUPPORT, 0]);
+    }
+
+    #[test]
+    fn slc_value_for_unknown_function_is_declined() {
+        let options: Arc<HashMap<u8, TelnetOption>> = Arc::new(HashMap::new());
+        let mut telnet = MuTelnet::new(options);
+
+        let changed = telnet.receive_slc(Bytes::from_static(&[99, SLC_VALUE, 7]));
+
+        assert!(changed);
+        assert_eq!(telnet.config.slc.get(&99), None);
+        let (op, payload) = expect_last_subnegotiate(&telnet.send_events, "a LINEMODE SLC reply");
+        assert_eq!(op, LINEMODE);
+        assert_eq!(payload.as_ref(), &[LINEMODE_SLC, 99, SLC_NOSUPPORT, 0]);
+    }
+
+    #[test]
+    fn slc_value_ack_of_our_own_proposal_is_not_re_acked() {
+        let options: Arc<HashMap<u8, TelnetOption>> = Arc::new(HashMap::new());
+        let mut telnet = MuTelnet::new(options);
+
+        let changed = telnet.receive_slc(Bytes::from_static(&[SLC_EOF, SLC_VALUE | SLC_ACK, 4]));
+
+        assert!(changed);
+        assert_eq!(telnet.config.slc.get(&SLC_EOF), Some(&4));
+        assert!(telnet.send_events.is_empty());
+    }
+
+    #[test]
+    fn charset_is_ignored_before_being_negotiated() {
+        let options: Arc<HashMap<u8, TelnetOption>> = Arc::new(HashMap::new());
+        let mut telnet = MuTelnet::new(options);
+
+        let mut payload = vec![CHARSET_REQUEST];
+        payload.extend_from_slice(b";UTF-8");
+
+        let changed = telnet.receive_charset(Bytes::from(payload));
+
+        assert!(!changed);
+        assert_eq!(telnet.config.encoding, "ascii");
+        assert!(telnet.send_events.is_empty());
+    }
+
+    #[test]
+    fn charset_accepted_rejects_unsupported_name() {
+        let options: Arc<HashMap<u8, TelnetOption>> = Arc::new(HashMap::new());
+        let mut telnet = MuTelnet::new(options);
+        telnet.op_state.insert(CHARSET, TelnetOptionState {
+            local: TelnetOptionPerspective { state: QState::Yes, queue: QQueue::Empty },
+            remote: TelnetOptionPerspective::default(),
+        });
+
+        let mut payload = vec![CHARSET_ACCEPTED];
+        payload.extend_from_slice(b"SHIFT-JIS");
+        let changed = telnet.receive_charset(Bytes::from(payload));
+
+        assert!(!changed);
+        assert_eq!(telnet.config.encoding, "ascii");
+    }
+
+    #[test]
+    fn charset_request_picks_first_supported_offer() {
+        let options: Arc<HashMap<u8, TelnetOption>> = Arc::new(HashMap::new());
+        let mut telnet = MuTelnet::new(options);
+        telnet.op_state.insert(CHARSET, TelnetOptionState {
+            local: TelnetOptionPerspective { state: QState::Yes, queue: QQueue::Empty },
+            remote: TelnetOptionPerspective::default(),
+        });
+
+        let mut payload = vec![CHARSET_REQUEST];
+        for name in [";KOI8-R", ";US-ASCII", ";UTF-8"] {
+            payload.extend_from_slice(name.as_bytes());
+        }
+
+        let changed = telnet.receive_charset(Bytes::from(payload));
+
+        // Picking US-ASCII leaves config.encoding at its "ascii" default, so
+        // this doesn't count as a config change even though negotiation
+        // completed and a reply was sent.
+        assert!(!changed);
+        assert_eq!(telnet.config.encoding, "ascii");
+        let (op, payload) = expect_last_subnegotiate(&telnet.send_events, "a CHARSET ACCEPTED reply");
+        assert_eq!(op, CHARSET);
+        assert_eq!(payload.as_ref(), b"\x02US-ASCII");
+    }
+
+    #[test]
+    fn charset_accepted_normalizes_peer_casing() {
+        let options: Arc<HashMap<u8, TelnetOption>> = Arc::new(HashMap::new());
+        let mut telnet = MuTelnet::new(options);
+        telnet.op_state.insert(CHARSET, TelnetOptionState {
+            local: TelnetOptionPerspective { state: QState::Yes, queue: QQueue::Empty },
+            remote: TelnetOptionPerspective::default(),
+        });
+
+        let mut payload = vec![CHARSET_ACCEPTED];
+        payload.extend_from_slice(b"utf-8");
+        let changed = telnet.receive_charset(Bytes::from(payload));
+
+        assert!(changed);
+        assert_eq!(telnet.config.encoding, "utf8");
+    }
+
+    #[test]
+    fn send_prompt_uses_eor_when_negotiated() {
+        let options: Arc<HashMap<u8, TelnetOption>> = Arc::new(HashMap::new());
+        let mut telnet = MuTelnet::new(options);
+        telnet.op_state.insert(TELOPT_EOR, TelnetOptionState {
+            local: TelnetOptionPerspective { state: QState::Yes, queue: QQueue::Empty },
+            remote: TelnetOptionPerspective::default(),
+        });
+
+        telnet.send_prompt("> ");
+
+        assert_eq!(expect_last_command(&telnet.send_events, "an IAC EOR"), EOR);
+    }
+
+    #[test]
+    fn send_prompt_falls_back_to_ga_without_eor() {
+        let options: Arc<HashMap<u8, TelnetOption>> = Arc::new(HashMap::new());
+        let mut telnet = MuTelnet::new(options);
+
+        telnet.send_prompt("> ");
+
+        assert_eq!(expect_last_command(&telnet.send_events, "an IAC GA"), GA);
+    }
+
+    #[test]
+    fn eor_prompt_unaffected_by_remote_perspective_dropping() {
+        let options: Arc<HashMap<u8, TelnetOption>> = Arc::new(HashMap::new());
+        let mut telnet = MuTelnet::new(options);
+        telnet.op_state.insert(TELOPT_EOR, TelnetOptionState::default());
+
+        telnet.set_local(TELOPT_EOR, QState::Yes, QQueue::Empty);
+        telnet.set_remote(TELOPT_EOR, QState::Yes, QQueue::Empty);
+        telnet.set_remote(TELOPT_EOR, QState::No, QQueue::Empty);
+
+        telnet.send_prompt("> ");
+
+        assert_eq!(
+            expect_last_command(&telnet.send_events, "an IAC EOR (local grant should survive remote dropping)"),
+            EOR
+        );
+    }
 }